@@ -0,0 +1,94 @@
+use std::io;
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{Command, RawMessage};
+
+/// A `tokio_util` codec for Ostrich packets.
+///
+/// `from_raw`/`to_raw` assume the whole packet is already in hand, which
+/// doesn't hold for a TCP stream where reads can land mid-packet. Wrapping
+/// a socket in `Framed<_, OstrichCodec>` buffers partial reads and yields
+/// a `Command` only once a full frame is available.
+pub struct OstrichCodec;
+
+impl Decoder for OstrichCodec {
+    type Item = Command;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Command>, io::Error> {
+        match RawMessage::from_framed(src) {
+            Ok((command, consumed)) => {
+                src.advance(consumed);
+                Ok(Some(command))
+            },
+            // Not enough bytes buffered yet, wait for more to arrive
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Encoder<Command> for OstrichCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Command, dst: &mut BytesMut) -> Result<(), io::Error> {
+        let raw = RawMessage::to_raw(&item)?;
+        dst.extend_from_slice(&raw);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_decode_waits_for_split_frame() {
+    let command = Command::Msg("alice".to_string(), "bob".to_string(), "hi".to_string());
+    let raw = RawMessage::to_raw(&command).unwrap();
+    let split = raw.len() / 2;
+
+    let mut codec = OstrichCodec;
+    let mut src = BytesMut::from(&raw[..split]);
+    assert!(codec.decode(&mut src).unwrap().is_none());
+
+    src.extend_from_slice(&raw[split..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(command));
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_decode_yields_back_to_back_frames_from_one_buffer() {
+    let first = Command::Ok;
+    let second = Command::Get;
+
+    let mut src = BytesMut::new();
+    src.extend_from_slice(&RawMessage::to_raw(&first).unwrap());
+    src.extend_from_slice(&RawMessage::to_raw(&second).unwrap());
+
+    let mut codec = OstrichCodec;
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(first));
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(second));
+    assert!(codec.decode(&mut src).unwrap().is_none());
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_decode_rejects_mismatched_version() {
+    let mut raw = RawMessage::to_raw(&Command::Ok).unwrap();
+    raw[0] = crate::PROTO_VERSION + 1;
+
+    let mut codec = OstrichCodec;
+    let mut src = BytesMut::from(&raw[..]);
+    let err = codec.decode(&mut src).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_encode_then_decode_roundtrip() {
+    let command = Command::Join("#general".to_string());
+
+    let mut codec = OstrichCodec;
+    let mut buf = BytesMut::new();
+    codec.encode(command.clone(), &mut buf).unwrap();
+
+    assert_eq!(codec.decode(&mut buf).unwrap(), Some(command));
+}
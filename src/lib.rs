@@ -1,34 +1,59 @@
 use std::io;
 use std::fmt;
 use std::ops::Range;
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
+use std::collections::HashMap;
 
-use num_derive::FromPrimitive;    
+use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use serde::{Serialize, Deserialize};
+
+pub mod codec;
 
 /// Ostrich packet size, 1024 Bytes (1K)
 pub const PCK_SIZE: usize = 1024;
 
+/// Largest frame length a variable-length text field (see
+/// `RawMessage::encode_frame_len`) will accept. Bounds how much a peer can
+/// make us buffer on the strength of a single claimed length prefix, since
+/// that prefix can claim up to `u64::MAX` bytes before any of the actual
+/// payload has arrived.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Current version of the Ostrich wire protocol.
+///
+/// Sent as the very first byte of every packet so that two peers built
+/// against different layouts can detect the mismatch instead of
+/// misparsing each other's bytes.
+pub const PROTO_VERSION: u8 = 1;
+
 /*  Ostrich packet format:
  *  Some fields are empty for some messages.
  *  For example, when sending an Error command
  *  fields sender and receiver are empty.
  *
+ * 1   B : Protocol version (PROTO_VERSION)
  * 1   B : Command code (0)
  * 1   B : Sender's name length
  * 16  B : Sender name or empty
  * 1   B : Receiver's name length
  * 16  B : Receiver or empty
  * 2   B : Text length in bytes
- * 991 B : Text or empty
+ * 857 B : Text or empty
+ * 1   B : Tag section length
+ * 128 B : IRCv3-style `k=v;k=v` tag blob or empty
  */
-pub const CMD_BYTES: Range<usize> = (0..0);
-pub const SENDER_LEN: usize = 1;
-pub const SENDER_BYTES: Range<usize> = (2..17);
-pub const RECV_LEN: usize = 18;
-pub const RECV_BYTES: Range<usize> = (19..34);
-pub const TXT_LEN: Range<usize> = (35..36);
-pub const TXT_BYTES: Range<usize> = (37..1023);
+pub const VERSION_BYTE: usize = 0;
+pub const CMD_BYTE: usize = 1;
+pub const CMD_BYTES: Range<usize> = (1..1);
+pub const SENDER_LEN: usize = 2;
+pub const SENDER_BYTES: Range<usize> = (3..19);
+pub const RECV_LEN: usize = 19;
+pub const RECV_BYTES: Range<usize> = (20..36);
+pub const TXT_LEN: Range<usize> = (36..38);
+pub const TXT_BYTES: Range<usize> = (38..895);
+pub const TAG_LEN: usize = 895;
+pub const TAG_BYTES: Range<usize> = (896..1024);
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
@@ -45,19 +70,33 @@ pub enum CommandCode {
     ListUsr = 8,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ListUsrOperation {
     Add,
     Remove,
 }
 
+/// Machine-readable reason behind a `Command::Err`, so a peer can react to
+/// a specific failure instead of pattern-matching on free-form text.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum ErrorCode {
+    Unknown = 0,
+    AuthFailed = 1,
+    NoSuchUser = 2,
+    NoSuchGroup = 3,
+    NameTooLong = 4,
+    Malformed = 5,
+}
+
 // TODO : Descriptions
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Command {
     // Ok
     Ok,
-    // Error message: text (error)
-    Err(String),                 
+    // Error message: code (reason), text (error)
+    Err(ErrorCode, String),
     // Get, NOTE: Maybe deleted in the future
     Get,
     // Send message: (sender, receiver, text)
@@ -78,7 +117,7 @@ impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Command::Ok => write!(f, "OK"),
-            Command::Err(err) => write!(f, "ERROR: {}", err),
+            Command::Err(code, err) => write!(f, "ERROR ({:?}): {}", code, err),
             Command::Get => write!(f, "GET"),
             Command::Msg(s, t, m) => write!(f, "{} -> {} : {}", s, t, m),
             Command::End => write!(f, "END"),
@@ -87,48 +126,155 @@ impl fmt::Display for Command {
             Command::Leave(gname) => write!(f, "LEAVE: {}", gname),
             Command::ListUsr(gname, ListUsrOperation::Add, users) => write!(f, 
                 "LIST: group {} ADD users: {}", gname, users),
-            Command::ListUsr(gname, ListUsrOperation::Remove, users) => write!(f, 
+            Command::ListUsr(gname, ListUsrOperation::Remove, users) => write!(f,
                 "LIST: group {} REMOVE users: {}", gname, users),
         }
     }
 }
 
+/// A `Command` alongside optional IRCv3-style message tags (send time, a
+/// message id for dedup/acks, reply-to references, ...) that don't fit
+/// anywhere in the fixed packet layout.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaggedCommand {
+    pub command: Command,
+    pub tags: HashMap<String, String>,
+}
+
+impl TaggedCommand {
+    pub fn new(command: Command) -> Self {
+        TaggedCommand { command, tags: HashMap::new() }
+    }
+}
+
+/// Builds a tag map for `TaggedCommand` without the `HashMap::new()` /
+/// `insert` boilerplate at call sites, e.g. `header!{"id" => "42"}`.
+#[macro_export]
+macro_rules! header {
+    ($($key:expr => $val:expr),* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut tags = ::std::collections::HashMap::new();
+        $( tags.insert(($key).to_string(), ($val).to_string()); )*
+        tags
+    }};
+}
+
+/// Selects which of `RawMessage`'s wire encodings to use, so a caller can
+/// negotiate formats with a peer (e.g. over the version byte, or an
+/// out-of-band handshake) instead of committing to one at compile time.
+/// Existing peers keep working: `Fixed` and `Framed` are unchanged, and
+/// `MsgPack` is purely additive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+#[repr(u8)]
+pub enum WireFormat {
+    /// The original fixed `PCK_SIZE`-byte layout (`to_raw_fixed`/`from_raw`).
+    Fixed = 0,
+    /// The compact variable-length framing (`to_raw`/`from_framed`).
+    Framed = 1,
+    /// MessagePack via serde (`to_msgpack`/`from_msgpack`).
+    MsgPack = 2,
+}
+
 pub struct RawMessage;
 
 impl RawMessage {
     
+    /// Reads the length of the message text out of the TXT_LEN segment,
+    /// shared by the lossy and strict text decoders below.
+    ///
+    /// TXT_LEN previously read as `raw[TXT_LEN.start]`/`raw[TXT_LEN.end]`,
+    /// which only worked by accident because the range's length was 1
+    /// short of the 2 bytes it actually describes. It is now a proper
+    /// half-open 2-byte range, so a plain slice conversion is correct.
+    fn text_len(raw: &[u8]) -> usize {
+        let bytes: [u8; 2] = raw[TXT_LEN].try_into().expect("TXT_LEN is 2 bytes wide");
+        u16::from_ne_bytes(bytes) as usize
+    }
+
     /// Parses the text segment of a given byte buffer into a string.
-    /// It cares about the text length parameter given in the TXT_LEN 
+    /// It cares about the text length parameter given in the TXT_LEN
     /// segment of the message.
-    fn parse_text(raw: &[u8]) -> String {
-        // Get the length of the message text
-        let mut range = [0u8;2];
-        range[0] = raw[TXT_LEN.start];
-        range[1] = raw[TXT_LEN.end];
-        let n: usize = u16::from_ne_bytes(range) as usize;
+    fn parse_text(raw: &[u8]) -> Result<String, io::Error> {
+        let n = RawMessage::text_len(raw);
+        RawMessage::check_field_len(n, TXT_BYTES.len(), "text")?;
         // Convert txt to string
-        let text = String::from_utf8_lossy(&raw[TXT_BYTES][..n]);
-        text.to_string()
+        Ok(String::from_utf8_lossy(&raw[TXT_BYTES][..n]).to_string())
     }
 
-pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
-        // Check the first byte for command code
-        match FromPrimitive::from_u8(raw[0]) {
+    /// Same field extraction as `parse_text`, but rejects a text segment
+    /// that isn't valid UTF-8 instead of replacing bad bytes with U+FFFD.
+    fn parse_text_strict(raw: &[u8]) -> Result<String, io::Error> {
+        let n = RawMessage::text_len(raw);
+        RawMessage::check_field_len(n, TXT_BYTES.len(), "text")?;
+        RawMessage::checked_str(&raw[TXT_BYTES][..n], TXT_BYTES.start)
+    }
+
+    /// Rejects a declared field length that would read past the end of
+    /// that field's region, instead of letting the later slice index panic.
+    fn check_field_len(n: usize, max: usize, field: &str) -> Result<(), io::Error> {
+        if n > max {
+            Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{} length {} exceeds the {} byte limit", field, n, max)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Converts `bytes` to a `String`, failing with the buffer-relative
+    /// byte offset of the first invalid sequence instead of silently
+    /// substituting U+FFFD.
+    fn checked_str(bytes: &[u8], field_offset: usize) -> Result<String, io::Error> {
+        std::str::from_utf8(bytes)
+            .map(|s| s.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData,
+                    format!("invalid utf-8 at byte offset {}", field_offset + e.valid_up_to())))
+    }
+
+    /// Reads the protocol version byte of a raw packet without parsing the
+    /// rest of it, so a peer can decide whether it is safe to continue.
+    /// Returns `None` if `raw` is too short to even hold a version byte,
+    /// since this is meant to be called on partial/untrusted buffers.
+    pub fn version(raw: &[u8]) -> Option<u8> {
+        raw.get(VERSION_BYTE).copied()
+    }
+
+    pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
+        // Reject anything shorter than a full packet before indexing into it
+        if raw.len() < PCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("packet is {} bytes, expected at least {}", raw.len(), PCK_SIZE)));
+        }
+        // Reject packets speaking a protocol version we don't understand.
+        // `raw.len() >= PCK_SIZE` was just checked, so the version byte
+        // is always present here.
+        let version = RawMessage::version(raw).expect("raw.len() >= PCK_SIZE checked above");
+        if version != PROTO_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("unsupported protocol version {}", version)));
+        }
+        // Check the command code byte
+        match FromPrimitive::from_u8(raw[CMD_BYTE]) {
             Some(CommandCode::Ok) => Ok(Command::Ok),
             Some(CommandCode::Err) => {
+                // The error code rides in the (otherwise unused for Err
+                // messages) SENDER_LEN byte
+                let code = FromPrimitive::from_u8(raw[SENDER_LEN])
+                    .unwrap_or(ErrorCode::Unknown);
                 // Get the error message from the text segment
-                Ok(Command::Err(RawMessage::parse_text(&raw)))
+                Ok(Command::Err(code, RawMessage::parse_text(&raw)?))
             },
             Some(CommandCode::Get) => Ok(Command::Get),
             Some(CommandCode::Msg) => {
                 // Get sender name
                 let n = raw[SENDER_LEN] as usize;
+                RawMessage::check_field_len(n, SENDER_BYTES.len(), "sender name")?;
                 let sender = String::from_utf8_lossy(&raw[SENDER_BYTES][..n]);
                 // Get receiver name
                 let n = raw[RECV_LEN] as usize;
+                RawMessage::check_field_len(n, RECV_BYTES.len(), "receiver name")?;
                 let recv = String::from_utf8_lossy(&raw[RECV_BYTES][..n]);
-                // Parse the message text into a string 
-                let text = RawMessage::parse_text(&raw);
+                // Parse the message text into a string
+                let text = RawMessage::parse_text(&raw)?;
 
                 Ok(Command::Msg(sender.to_string(), recv.to_string(), text))
             },
@@ -136,31 +282,35 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
             Some(CommandCode::Usr) => {
                 // Get sender's username
                 let n = raw[SENDER_LEN] as usize;
+                RawMessage::check_field_len(n, SENDER_BYTES.len(), "sender name")?;
                 let username = String::from_utf8_lossy(&raw[SENDER_BYTES][..n]);
-                // Get password from the text segment 
-                let password = RawMessage::parse_text(&raw);
+                // Get password from the text segment
+                let password = RawMessage::parse_text(&raw)?;
 
                 Ok(Command::Usr(username.to_string(), password))
             },
             Some(CommandCode::Join) => {
                 // Get group's name length
                 let n = raw[RECV_LEN] as usize;
+                RawMessage::check_field_len(n, RECV_BYTES.len(), "group name")?;
                 // Transform bytes to utf-8 string
                 let gname = String::from_utf8_lossy(&raw[RECV_BYTES][..n]);
-                
+
                 Ok(Command::Join(gname.to_string()))
             },
             Some(CommandCode::Leave) => {
                 // Get group's name length
                 let n = raw[RECV_LEN] as usize;
+                RawMessage::check_field_len(n, RECV_BYTES.len(), "group name")?;
                 // Transform bytes to utf-8 string
                 let gname = String::from_utf8_lossy(&raw[RECV_BYTES][..n]);
-                
+
                 Ok(Command::Leave(gname.to_string()))
             },
             Some(CommandCode::ListUsr) => {
                 // Get group's name
                 let n = raw[SENDER_LEN] as usize;
+                RawMessage::check_field_len(n, SENDER_BYTES.len(), "group name")?;
                 let gname = String::from_utf8_lossy(&raw[SENDER_BYTES][..n]);
                 // Operation is stored in RECV_LEN section
                 let op = if raw[RECV_LEN] == 254 {
@@ -171,17 +321,93 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
                     return Err(io::Error::new(io::ErrorKind::InvalidData,
                             "ListUsr command's operation specification section corrupted"));
                 };
-                // Parse the message text into a string 
-                let text = RawMessage::parse_text(&raw);
+                // Parse the message text into a string
+                let text = RawMessage::parse_text(&raw)?;
 
                 Ok(Command::ListUsr(gname.to_string(), op, text))
             },
-            None => Err(io::Error::new(io::ErrorKind::InvalidData, 
-                                       format!("Incorrect command byte: {}", raw[0]))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("Incorrect command byte: {}", raw[CMD_BYTE]))),
         }
     }
 
-    fn put(buffer: &mut [u8], 
+    /// Same as `from_raw`, but rejects a packet outright if any of its
+    /// name/text fields is not valid UTF-8 instead of silently replacing
+    /// the offending bytes with U+FFFD.
+    pub fn from_raw_strict(raw: &[u8]) -> Result<Command, io::Error> {
+        if raw.len() < PCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("packet is {} bytes, expected at least {}", raw.len(), PCK_SIZE)));
+        }
+        let version = RawMessage::version(raw).expect("raw.len() >= PCK_SIZE checked above");
+        if version != PROTO_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("unsupported protocol version {}", version)));
+        }
+        match FromPrimitive::from_u8(raw[CMD_BYTE]) {
+            Some(CommandCode::Ok) => Ok(Command::Ok),
+            Some(CommandCode::Err) => {
+                let code = FromPrimitive::from_u8(raw[SENDER_LEN])
+                    .unwrap_or(ErrorCode::Unknown);
+                Ok(Command::Err(code, RawMessage::parse_text_strict(&raw)?))
+            },
+            Some(CommandCode::Get) => Ok(Command::Get),
+            Some(CommandCode::Msg) => {
+                let n = raw[SENDER_LEN] as usize;
+                RawMessage::check_field_len(n, SENDER_BYTES.len(), "sender name")?;
+                let sender = RawMessage::checked_str(&raw[SENDER_BYTES][..n], SENDER_BYTES.start)?;
+                let n = raw[RECV_LEN] as usize;
+                RawMessage::check_field_len(n, RECV_BYTES.len(), "receiver name")?;
+                let recv = RawMessage::checked_str(&raw[RECV_BYTES][..n], RECV_BYTES.start)?;
+                let text = RawMessage::parse_text_strict(&raw)?;
+
+                Ok(Command::Msg(sender, recv, text))
+            },
+            Some(CommandCode::End) => Ok(Command::End),
+            Some(CommandCode::Usr) => {
+                let n = raw[SENDER_LEN] as usize;
+                RawMessage::check_field_len(n, SENDER_BYTES.len(), "sender name")?;
+                let username = RawMessage::checked_str(&raw[SENDER_BYTES][..n], SENDER_BYTES.start)?;
+                let password = RawMessage::parse_text_strict(&raw)?;
+
+                Ok(Command::Usr(username, password))
+            },
+            Some(CommandCode::Join) => {
+                let n = raw[RECV_LEN] as usize;
+                RawMessage::check_field_len(n, RECV_BYTES.len(), "group name")?;
+                let gname = RawMessage::checked_str(&raw[RECV_BYTES][..n], RECV_BYTES.start)?;
+
+                Ok(Command::Join(gname))
+            },
+            Some(CommandCode::Leave) => {
+                let n = raw[RECV_LEN] as usize;
+                RawMessage::check_field_len(n, RECV_BYTES.len(), "group name")?;
+                let gname = RawMessage::checked_str(&raw[RECV_BYTES][..n], RECV_BYTES.start)?;
+
+                Ok(Command::Leave(gname))
+            },
+            Some(CommandCode::ListUsr) => {
+                let n = raw[SENDER_LEN] as usize;
+                RawMessage::check_field_len(n, SENDER_BYTES.len(), "group name")?;
+                let gname = RawMessage::checked_str(&raw[SENDER_BYTES][..n], SENDER_BYTES.start)?;
+                let op = if raw[RECV_LEN] == 254 {
+                    ListUsrOperation::Add
+                } else if raw[RECV_LEN] == 0 {
+                    ListUsrOperation::Remove
+                } else {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            "ListUsr command's operation specification section corrupted"));
+                };
+                let text = RawMessage::parse_text_strict(&raw)?;
+
+                Ok(Command::ListUsr(gname, op, text))
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("Incorrect command byte: {}", raw[CMD_BYTE]))),
+        }
+    }
+
+    fn put(buffer: &mut [u8],
            content: &[u8], 
            range: Range<usize>) -> Result<(), io::Error> {
         
@@ -192,31 +418,42 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
         }
         
-        if content.len() > range.len()+1 {
-            let err = format!("Content larger than range, data loss might occur: range {:?}, content len {}", 
+        if content.len() > range.len() {
+            let err = format!("Content larger than range, data loss might occur: range {:?}, content len {}",
                               range, content.len());
             return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
         }
-        
+
         content.iter()
             .enumerate()
-            .skip_while(|(i, _)| *i > range.end) // Check if the content size is larger than the range
+            .take_while(|(i, _)| *i < range.len()) // Never write past the end of the range
             .for_each(|(i, x)| buffer[range.start+i] = *x);
 
         Ok(())
     }
 
-    pub fn to_raw(command: &Command) -> Result<[u8; 1024], io::Error> {
+    /// Serializes a `Command` into the original fixed, 1024 Byte packet
+    /// layout. Kept around so callers that still expect a full `[u8; PCK_SIZE]`
+    /// buffer (and `from_raw`) keep working; new code should prefer the
+    /// variable-length `RawMessage::to_raw`.
+    pub fn to_raw_fixed(command: &Command) -> Result<[u8; 1024], io::Error> {
         // Init buffer
         // let mut buffer = BytesMut::with_capacity(PCK_SIZE);
         let mut buffer = [0u8; PCK_SIZE];
 
+        // Stamp the protocol version so the receiver can check compatibility
+        // before trusting the rest of the buffer
+        buffer[VERSION_BYTE] = PROTO_VERSION;
+
         // Set command code
         match command {
-            Command::Ok => buffer[0] = CommandCode::Ok as u8,
-            Command::Err(err) => {
+            Command::Ok => buffer[CMD_BYTE] = CommandCode::Ok as u8,
+            Command::Err(code, err) => {
                 // Set command code
-                buffer[0] = CommandCode::Err as u8;
+                buffer[CMD_BYTE] = CommandCode::Err as u8;
+                // Stash the error code in the SENDER_LEN byte, unused for
+                // Err messages otherwise
+                buffer[SENDER_LEN] = *code as u8;
                 // Set the error message length bytes
                 let err = err.as_bytes();
                 let n = RawMessage::compute_text_length(&err)?;
@@ -224,11 +461,11 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
                 // Append the error's bytes to the buffer's text section
                 RawMessage::put(&mut buffer, err, TXT_BYTES)?;
             },
-            Command::Get => buffer[0] = CommandCode::Get as u8,
-            Command::End => buffer[0] = CommandCode::End as u8,
+            Command::Get => buffer[CMD_BYTE] = CommandCode::Get as u8,
+            Command::End => buffer[CMD_BYTE] = CommandCode::End as u8,
             Command::Msg(s,r,t) => {
                 // Append MSG code
-                buffer[0] = CommandCode::Msg as u8;
+                buffer[CMD_BYTE] = CommandCode::Msg as u8;
                 // Add sender name
                 let s = s.as_bytes();
                 buffer[SENDER_LEN] = s.len() as u8;
@@ -246,7 +483,7 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
             },
             Command::Usr(username, password) => {
                 // Set USR command code
-                buffer[0] = CommandCode::Usr as u8;
+                buffer[CMD_BYTE] = CommandCode::Usr as u8;
                 // Set sender's username
                 let username = username.as_bytes();
                 buffer[SENDER_LEN] = username.len() as u8; // Set sender name size
@@ -260,7 +497,7 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
             },
             Command::Join(gname) => {
                 // Set JOIN command code
-                buffer[0] = CommandCode::Join as u8;
+                buffer[CMD_BYTE] = CommandCode::Join as u8;
                 // The name of the group to join is stored in the 
                 // targets space of the message
                 let gname = gname.as_bytes();
@@ -269,7 +506,7 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
             },
             Command::Leave(gname) => {
                 // Set LEAVE command code
-                buffer[0] = CommandCode::Leave as u8;
+                buffer[CMD_BYTE] = CommandCode::Leave as u8;
                 // The name of the group to leave is stored in the 
                 // targets space of the message
                 let gname = gname.as_bytes();
@@ -278,7 +515,7 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
             },
             Command::ListUsr(gname, op, users) => {
                 // Set ListUsr command code
-                buffer[0] = CommandCode::ListUsr as u8;
+                buffer[CMD_BYTE] = CommandCode::ListUsr as u8;
                 // Write gname's length and gname
                 buffer[SENDER_LEN] = gname.as_bytes().len() as u8;
                 RawMessage::put(&mut buffer, gname.as_bytes(), SENDER_BYTES)?;
@@ -297,7 +534,141 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
         }
         Ok(buffer)
     }
-    
+
+    /// Serializes a `TaggedCommand` into the fixed 1024 Byte layout, packing
+    /// the tag map into the tail reserved for it (TAG_LEN/TAG_BYTES)
+    /// alongside the usual `to_raw_fixed` encoding of the wrapped command.
+    pub fn to_raw_tagged(tagged: &TaggedCommand) -> Result<[u8; PCK_SIZE], io::Error> {
+        let mut buffer = RawMessage::to_raw_fixed(&tagged.command)?;
+        let blob = RawMessage::encode_tags(&tagged.tags)?;
+        buffer[TAG_LEN] = blob.len() as u8;
+        RawMessage::put(&mut buffer, &blob, TAG_BYTES)?;
+        Ok(buffer)
+    }
+
+    /// Escapes the delimiter characters `encode_tags` relies on (`;`, `=`,
+    /// space, `\`, CR, LF), mirroring IRCv3 message tag escaping so a key or
+    /// value containing one of them round-trips instead of corrupting the
+    /// blob.
+    fn escape_tag(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        for c in s.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                ';' => out.push_str("\\:"),
+                '=' => out.push_str("\\="),
+                ' ' => out.push_str("\\s"),
+                '\r' => out.push_str("\\r"),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out
+    }
+
+    /// Splits `s` on unescaped occurrences of `delim`, skipping any `delim`
+    /// preceded by an (unescaped) backslash. Used to tell a literal `;` or
+    /// `=` escaped by `escape_tag` apart from a real pair/key-value
+    /// separator.
+    fn split_unescaped(s: &str, delim: char) -> Vec<&str> {
+        let mut parts = Vec::new();
+        let mut start = 0;
+        let mut escaped = false;
+        for (i, c) in s.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delim {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+        }
+        parts.push(&s[start..]);
+        parts
+    }
+
+    /// Like `split_unescaped`, but only splits on the first unescaped
+    /// occurrence of `delim`, mirroring `str::split_once`.
+    fn split_once_unescaped(s: &str, delim: char) -> Option<(&str, &str)> {
+        let mut escaped = false;
+        for (i, c) in s.char_indices() {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == delim {
+                return Some((&s[..i], &s[i + c.len_utf8()..]));
+            }
+        }
+        None
+    }
+
+    /// Reverses `escape_tag`. An escape sequence not in the table above is
+    /// passed through with the backslash dropped, same as IRCv3.
+    fn unescape_tag(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('\\') => out.push('\\'),
+                Some(':') => out.push(';'),
+                Some('=') => out.push('='),
+                Some('s') => out.push(' '),
+                Some('r') => out.push('\r'),
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => {},
+            }
+        }
+        out
+    }
+
+    /// Packs a tag map into the IRCv3-style `k=v;k=v` blob stored in the
+    /// tag section of the buffer. Keys and values are escaped so that `;`,
+    /// `=`, space, `\`, CR and LF in tag data can't be mistaken for
+    /// delimiters. Keys are sorted so the encoding is deterministic, which
+    /// keeps round-trip tests stable.
+    fn encode_tags(tags: &HashMap<String, String>) -> Result<Vec<u8>, io::Error> {
+        let mut parts: Vec<String> = tags.iter()
+            .map(|(k, v)| format!("{}={}", RawMessage::escape_tag(k), RawMessage::escape_tag(v)))
+            .collect();
+        parts.sort();
+        let blob = parts.join(";");
+        if blob.len() > TAG_BYTES.len() {
+            let err = format!("tag section of {} bytes exceeds the {} byte limit",
+                              blob.len(), TAG_BYTES.len());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+        }
+        Ok(blob.into_bytes())
+    }
+
+    /// Parses a fixed-layout packet into a `TaggedCommand`, decoding the tag
+    /// section back into a map. An empty tag section (length byte 0) is
+    /// treated as "no tags" rather than an error.
+    pub fn from_raw_tagged(raw: &[u8]) -> Result<TaggedCommand, io::Error> {
+        let command = RawMessage::from_raw(raw)?;
+        let n = raw[TAG_LEN] as usize;
+        RawMessage::check_field_len(n, TAG_BYTES.len(), "tags")?;
+        let tags = if n == 0 {
+            HashMap::new()
+        } else {
+            let blob = String::from_utf8_lossy(&raw[TAG_BYTES][..n]);
+            RawMessage::split_unescaped(&blob, ';').into_iter()
+                .filter(|pair| !pair.is_empty())
+                .filter_map(|pair| {
+                    let (k, v) = RawMessage::split_once_unescaped(pair, '=')?;
+                    Some((RawMessage::unescape_tag(k), RawMessage::unescape_tag(v)))
+                })
+                .collect()
+        };
+        Ok(TaggedCommand { command, tags })
+    }
+
     /// Return's a 2Byte representation of the length of a given byte buffer.
     /// # Errors:
     /// Returns an InvalidInput error if the length of the buffer can 
@@ -305,38 +676,304 @@ pub fn from_raw(raw: &[u8]) -> Result<Command, io::Error> {
     fn compute_text_length(buffer: &[u8]) -> Result<[u8;2], io::Error> {
         match u16::try_from(buffer.len()) {
             Ok(n) => Ok(n.to_ne_bytes()),
-            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, 
+            Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
                                         "Error message length exceded"))
         }
     }
+
+    /// Builds an `io::Error` signalling that `src` does not (yet) hold a
+    /// full frame, so the caller can buffer more bytes and retry.
+    fn incomplete() -> io::Error {
+        io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete ostrich frame")
+    }
+
+    /// Encodes `n` using the WebSocket-style variable length prefix: values
+    /// up to 125 are stored directly, 126 means the next 2 bytes are a
+    /// big-endian u16, and 127 means the next 8 bytes are a big-endian u64.
+    fn encode_frame_len(n: usize) -> Vec<u8> {
+        if n <= 125 {
+            vec![n as u8]
+        } else if n <= u16::MAX as usize {
+            let mut v = vec![126u8];
+            v.extend_from_slice(&(n as u16).to_be_bytes());
+            v
+        } else {
+            let mut v = vec![127u8];
+            v.extend_from_slice(&(n as u64).to_be_bytes());
+            v
+        }
+    }
+
+    /// Decodes a length prefix written by `RawMessage::encode_frame_len`.
+    /// Returns the decoded length and the number of header bytes consumed.
+    /// Rejects a claimed length over `MAX_FRAME_LEN` immediately, instead of
+    /// buffering forever (or overflowing downstream arithmetic) while
+    /// waiting for a peer's claimed-huge frame to fully arrive.
+    fn decode_frame_len(src: &[u8]) -> Result<(usize, usize), io::Error> {
+        let (n, header) = match src.first() {
+            None => return Err(RawMessage::incomplete()),
+            Some(126) => {
+                if src.len() < 3 {
+                    return Err(RawMessage::incomplete());
+                }
+                let n = u16::from_be_bytes([src[1], src[2]]) as usize;
+                (n, 3)
+            },
+            Some(127) => {
+                if src.len() < 9 {
+                    return Err(RawMessage::incomplete());
+                }
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&src[1..9]);
+                (u64::from_be_bytes(bytes) as usize, 9)
+            },
+            Some(&n) => (n as usize, 1),
+        };
+        if n > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("frame length {} exceeds the {} byte limit", n, MAX_FRAME_LEN)));
+        }
+        Ok((n, header))
+    }
+
+    /// Appends a small (<= 255 Byte) length-prefixed field such as a sender
+    /// or group name, the same shape `SENDER_LEN`/`RECV_LEN` use in the
+    /// fixed layout.
+    fn push_small_field(buf: &mut Vec<u8>, field: &[u8]) -> Result<(), io::Error> {
+        let n = u8::try_from(field.len()).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidInput, "field longer than 255 bytes"))?;
+        buf.push(n);
+        buf.extend_from_slice(field);
+        Ok(())
+    }
+
+    /// Reads a field written by `RawMessage::push_small_field`.
+    fn read_small_field(src: &[u8]) -> Result<(String, usize), io::Error> {
+        let n = *src.first().ok_or_else(RawMessage::incomplete)? as usize;
+        if src.len() < 1 + n {
+            return Err(RawMessage::incomplete());
+        }
+        Ok((String::from_utf8_lossy(&src[1..1 + n]).to_string(), 1 + n))
+    }
+
+    /// Appends a text field (error message, password, message body, ...)
+    /// using the compact frame-length encoding instead of a fixed-size
+    /// region, so short messages don't drag a near-1KB buffer with them.
+    fn push_framed_text(buf: &mut Vec<u8>, text: &[u8]) {
+        buf.extend(RawMessage::encode_frame_len(text.len()));
+        buf.extend_from_slice(text);
+    }
+
+    /// Reads a text field written by `RawMessage::push_framed_text`.
+    fn read_framed_text(src: &[u8]) -> Result<(String, usize), io::Error> {
+        let (n, header) = RawMessage::decode_frame_len(src)?;
+        // `n` is attacker-controlled (it rides a wire length prefix), so
+        // compare against the remaining buffer with a saturating subtraction
+        // rather than adding `header + n` first: that addition can overflow
+        // for a claimed length near `usize::MAX`, panicking the process
+        // before we ever get a chance to reject it as incomplete/too large.
+        if n > src.len().saturating_sub(header) {
+            return Err(RawMessage::incomplete());
+        }
+        let text = String::from_utf8_lossy(&src[header..header + n]);
+        Ok((text.to_string(), header + n))
+    }
+
+    /// Serializes a `Command` into a `Vec<u8>` sized to its actual
+    /// content: a version byte, a command byte, and then only as many
+    /// bytes as each field needs, instead of always emitting a full
+    /// `PCK_SIZE` buffer.
+    pub fn to_raw(command: &Command) -> Result<Vec<u8>, io::Error> {
+        let mut buf = Vec::new();
+        buf.push(PROTO_VERSION);
+
+        match command {
+            Command::Ok => buf.push(CommandCode::Ok as u8),
+            Command::Err(code, err) => {
+                buf.push(CommandCode::Err as u8);
+                buf.push(*code as u8);
+                RawMessage::push_framed_text(&mut buf, err.as_bytes());
+            },
+            Command::Get => buf.push(CommandCode::Get as u8),
+            Command::End => buf.push(CommandCode::End as u8),
+            Command::Msg(s, r, t) => {
+                buf.push(CommandCode::Msg as u8);
+                RawMessage::push_small_field(&mut buf, s.as_bytes())?;
+                RawMessage::push_small_field(&mut buf, r.as_bytes())?;
+                RawMessage::push_framed_text(&mut buf, t.as_bytes());
+            },
+            Command::Usr(username, password) => {
+                buf.push(CommandCode::Usr as u8);
+                RawMessage::push_small_field(&mut buf, username.as_bytes())?;
+                RawMessage::push_framed_text(&mut buf, password.as_bytes());
+            },
+            Command::Join(gname) => {
+                buf.push(CommandCode::Join as u8);
+                RawMessage::push_small_field(&mut buf, gname.as_bytes())?;
+            },
+            Command::Leave(gname) => {
+                buf.push(CommandCode::Leave as u8);
+                RawMessage::push_small_field(&mut buf, gname.as_bytes())?;
+            },
+            Command::ListUsr(gname, op, users) => {
+                buf.push(CommandCode::ListUsr as u8);
+                RawMessage::push_small_field(&mut buf, gname.as_bytes())?;
+                buf.push(match op {
+                    ListUsrOperation::Add => 254,
+                    ListUsrOperation::Remove => 0,
+                });
+                RawMessage::push_framed_text(&mut buf, users.as_bytes());
+            },
+        }
+        Ok(buf)
+    }
+
+    /// Parses a `Command` out of a buffer framed by `RawMessage::to_raw`,
+    /// returning the command together with the number of bytes it consumed
+    /// so the caller can advance past it in a larger, possibly incomplete,
+    /// stream. Returns an `UnexpectedEof` error when `src` does not yet hold
+    /// a full frame.
+    pub fn from_framed(src: &[u8]) -> Result<(Command, usize), io::Error> {
+        if src.len() < 2 {
+            return Err(RawMessage::incomplete());
+        }
+        let version = src[VERSION_BYTE];
+        if version != PROTO_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("unsupported protocol version {}", version)));
+        }
+
+        let code = src[CMD_BYTE];
+        let mut pos = 2;
+
+        let command = match FromPrimitive::from_u8(code) {
+            Some(CommandCode::Ok) => Command::Ok,
+            Some(CommandCode::Err) => {
+                let err_code = *src.get(pos).ok_or_else(RawMessage::incomplete)?;
+                pos += 1;
+                let err_code = FromPrimitive::from_u8(err_code).unwrap_or(ErrorCode::Unknown);
+                let (text, n) = RawMessage::read_framed_text(&src[pos..])?;
+                pos += n;
+                Command::Err(err_code, text)
+            },
+            Some(CommandCode::Get) => Command::Get,
+            Some(CommandCode::End) => Command::End,
+            Some(CommandCode::Msg) => {
+                let (sender, n) = RawMessage::read_small_field(&src[pos..])?;
+                pos += n;
+                let (recv, n) = RawMessage::read_small_field(&src[pos..])?;
+                pos += n;
+                let (text, n) = RawMessage::read_framed_text(&src[pos..])?;
+                pos += n;
+                Command::Msg(sender, recv, text)
+            },
+            Some(CommandCode::Usr) => {
+                let (username, n) = RawMessage::read_small_field(&src[pos..])?;
+                pos += n;
+                let (password, n) = RawMessage::read_framed_text(&src[pos..])?;
+                pos += n;
+                Command::Usr(username, password)
+            },
+            Some(CommandCode::Join) => {
+                let (gname, n) = RawMessage::read_small_field(&src[pos..])?;
+                pos += n;
+                Command::Join(gname)
+            },
+            Some(CommandCode::Leave) => {
+                let (gname, n) = RawMessage::read_small_field(&src[pos..])?;
+                pos += n;
+                Command::Leave(gname)
+            },
+            Some(CommandCode::ListUsr) => {
+                let (gname, n) = RawMessage::read_small_field(&src[pos..])?;
+                pos += n;
+                let op = *src.get(pos).ok_or_else(RawMessage::incomplete)?;
+                pos += 1;
+                let op = if op == 254 {
+                    ListUsrOperation::Add
+                } else if op == 0 {
+                    ListUsrOperation::Remove
+                } else {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                            "ListUsr command's operation specification section corrupted"));
+                };
+                let (users, n) = RawMessage::read_framed_text(&src[pos..])?;
+                pos += n;
+                Command::ListUsr(gname, op, users)
+            },
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("Incorrect command byte: {}", code))),
+        };
+
+        Ok((command, pos))
+    }
+
+    /// Serializes a `Command` as MessagePack instead of the hand-rolled
+    /// fixed/framed layouts. Unlike the fixed layout, this isn't bound by its
+    /// 16-byte name or 857-byte text ceilings, and new `Command` variants need no
+    /// change here to be supported.
+    pub fn to_msgpack(command: &Command) -> Result<Vec<u8>, io::Error> {
+        rmp_serde::to_vec(command)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Parses a `Command` previously produced by `to_msgpack`.
+    pub fn from_msgpack(src: &[u8]) -> Result<Command, io::Error> {
+        rmp_serde::from_slice(src)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Serializes `command` using the given `WireFormat`, so the caller can
+    /// pick the encoding at runtime (e.g. after negotiating with a peer)
+    /// instead of hard-coding one of `to_raw_fixed`/`to_raw`/`to_msgpack`.
+    pub fn encode(format: WireFormat, command: &Command) -> Result<Vec<u8>, io::Error> {
+        match format {
+            WireFormat::Fixed => RawMessage::to_raw_fixed(command).map(|buf| buf.to_vec()),
+            WireFormat::Framed => RawMessage::to_raw(command),
+            WireFormat::MsgPack => RawMessage::to_msgpack(command),
+        }
+    }
+
+    /// Parses a `Command` out of `src`, dispatching to the decoder for the
+    /// given `WireFormat`. The counterpart to `encode`.
+    pub fn decode(format: WireFormat, src: &[u8]) -> Result<Command, io::Error> {
+        match format {
+            WireFormat::Fixed => RawMessage::from_raw(src),
+            WireFormat::Framed => RawMessage::from_framed(src).map(|(command, _)| command),
+            WireFormat::MsgPack => RawMessage::from_msgpack(src),
+        }
+    }
 }
 
 #[test]
 fn test_ok() {
     let command = Command::Ok; 
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 0);
+    assert_eq!(mesg[CMD_BYTE], 0);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
 #[test]
 fn test_get() {
     let command = Command::Get; 
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 2);
+    assert_eq!(mesg[CMD_BYTE], 2);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
 #[test]
 fn test_err() {
-    let command = Command::Err("Some fatal error".to_string());
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let command = Command::Err(ErrorCode::Malformed, "Some fatal error".to_string());
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     println!("command to raw ok");
     let recovered = RawMessage::from_raw(&mesg).unwrap();
     println!("command from raw ok");
-    assert_eq!(mesg[0], 1);
+    assert_eq!(mesg[CMD_BYTE], 1);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
@@ -346,18 +983,20 @@ fn test_msg() {
                                "receiver".to_string(),
                                "The super secret message".to_string());
 
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 3);
+    assert_eq!(mesg[CMD_BYTE], 3);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
 #[test]
 fn test_end() {
     let command = Command::End; 
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 4);
+    assert_eq!(mesg[CMD_BYTE], 4);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
@@ -366,37 +1005,222 @@ fn test_usr() {
     let command = Command::Usr("sender".to_string(),
                                "The super secret password".to_string());
 
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 5);
+    assert_eq!(mesg[CMD_BYTE], 5);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
 #[test]
 fn test_join() {
     let command = Command::Join("#group_name".to_string());
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 6);
+    assert_eq!(mesg[CMD_BYTE], 6);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
 #[test]
 fn test_leave() {
     let command = Command::Leave("#group_name".to_string());
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 7);
+    assert_eq!(mesg[CMD_BYTE], 7);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
     assert_eq!(command, recovered);
 }
 
 #[test]
 fn test_listusr() {
-    let command = Command::ListUsr("#group_name".to_string(), 
+    let command = Command::ListUsr("#group_name".to_string(),
         ListUsrOperation::Add,
         "some\nmike\nkaixo\n".to_string());
-    let mesg = RawMessage::to_raw(&command).unwrap();
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
     let recovered = RawMessage::from_raw(&mesg).unwrap();
-    assert_eq!(mesg[0], 8);
+    assert_eq!(mesg[CMD_BYTE], 8);
+    assert_eq!(mesg[VERSION_BYTE], PROTO_VERSION);
+    assert_eq!(command, recovered);
+}
+
+#[test]
+fn test_framed_ok_is_compact() {
+    let command = Command::Ok;
+    let framed = RawMessage::to_raw(&command).unwrap();
+    // Just the version byte and the command byte, nowhere near PCK_SIZE
+    assert_eq!(framed.len(), 2);
+    let (recovered, consumed) = RawMessage::from_framed(&framed).unwrap();
+    assert_eq!(consumed, framed.len());
+    assert_eq!(command, recovered);
+}
+
+#[test]
+fn test_framed_msg_roundtrip() {
+    let command = Command::Msg("sender".to_string(),
+                               "receiver".to_string(),
+                               "The super secret message".to_string());
+    let framed = RawMessage::to_raw(&command).unwrap();
+    assert!(framed.len() < PCK_SIZE);
+    let (recovered, consumed) = RawMessage::from_framed(&framed).unwrap();
+    assert_eq!(consumed, framed.len());
+    assert_eq!(command, recovered);
+}
+
+#[test]
+fn test_framed_large_text_uses_u16_length() {
+    // 200 bytes doesn't fit the direct 0..=125 range, exercising the
+    // "126" escape code of the frame length prefix.
+    let text = "x".repeat(200);
+    let command = Command::Err(ErrorCode::Unknown, text);
+    let framed = RawMessage::to_raw(&command).unwrap();
+    assert_eq!(framed[3], 126);
+    let (recovered, consumed) = RawMessage::from_framed(&framed).unwrap();
+    assert_eq!(consumed, framed.len());
+    assert_eq!(command, recovered);
+}
+
+#[test]
+fn test_framed_incomplete_buffer() {
+    let command = Command::Join("#group_name".to_string());
+    let framed = RawMessage::to_raw(&command).unwrap();
+    let err = RawMessage::from_framed(&framed[..framed.len() - 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_framed_huge_length_prefix_is_rejected_not_panicking() {
+    // version, Err command code, error code byte, then a frame-length
+    // header claiming a near-u64::MAX length. Previously `header + n`
+    // overflowed and panicked instead of erroring.
+    let mut frame = vec![PROTO_VERSION, CommandCode::Err as u8, ErrorCode::Unknown as u8];
+    frame.push(127);
+    frame.extend_from_slice(&(u64::MAX - 1).to_be_bytes());
+    assert_eq!(frame.len(), 12);
+
+    let err = RawMessage::from_framed(&frame).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_from_raw_strict_accepts_valid_utf8() {
+    let command = Command::Msg("sender".to_string(),
+                               "receiver".to_string(),
+                               "The super secret message".to_string());
+    let mesg = RawMessage::to_raw_fixed(&command).unwrap();
+    let recovered = RawMessage::from_raw_strict(&mesg).unwrap();
     assert_eq!(command, recovered);
 }
+
+#[test]
+fn test_from_raw_strict_rejects_invalid_utf8() {
+    let command = Command::Msg("sender".to_string(),
+                               "receiver".to_string(),
+                               "hello".to_string());
+    let mut mesg = RawMessage::to_raw_fixed(&command).unwrap();
+    // Corrupt the text segment with a byte that is never valid UTF-8
+    mesg[TXT_BYTES.start] = 0xFF;
+
+    let err = RawMessage::from_raw_strict(&mesg).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    // Lossy decoding, on the other hand, happily swallows the bad byte
+    assert!(RawMessage::from_raw(&mesg).is_ok());
+}
+
+#[test]
+fn test_from_raw_rejects_short_buffer() {
+    let mesg = RawMessage::to_raw_fixed(&Command::Ok).unwrap();
+    let err = RawMessage::from_raw(&mesg[..PCK_SIZE - 1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_version_on_empty_buffer_returns_none() {
+    assert_eq!(RawMessage::version(&[]), None);
+}
+
+#[test]
+fn test_version_reads_first_byte() {
+    let mesg = RawMessage::to_raw_fixed(&Command::Ok).unwrap();
+    assert_eq!(RawMessage::version(&mesg), Some(PROTO_VERSION));
+}
+
+#[test]
+fn test_from_raw_rejects_oversized_field_length() {
+    let mut mesg = RawMessage::to_raw_fixed(&Command::Join("g".to_string())).unwrap();
+    // Claim a group name far larger than RECV_BYTES can actually hold
+    mesg[RECV_LEN] = 255;
+    let err = RawMessage::from_raw(&mesg).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_tagged_roundtrip() {
+    let tags = header!{"id" => "42", "ts" => "1690000000"};
+    let tagged = TaggedCommand {
+        command: Command::Msg("alice".to_string(), "bob".to_string(), "hi".to_string()),
+        tags,
+    };
+    let mesg = RawMessage::to_raw_tagged(&tagged).unwrap();
+    let parsed = RawMessage::from_raw_tagged(&mesg).unwrap();
+    assert_eq!(parsed, tagged);
+}
+
+#[test]
+fn test_tagged_empty_tags_is_tolerated() {
+    let tagged = TaggedCommand::new(Command::Ok);
+    let mesg = RawMessage::to_raw_tagged(&tagged).unwrap();
+    let parsed = RawMessage::from_raw_tagged(&mesg).unwrap();
+    assert_eq!(parsed.command, Command::Ok);
+    assert!(parsed.tags.is_empty());
+}
+
+#[test]
+fn test_tagged_roundtrip_escapes_delimiters() {
+    // A value containing the blob's own delimiters (';', '=') and escape
+    // character ('\\') must come back unchanged instead of being split
+    // into extra tags or truncated.
+    let tags = header!{"note" => "a;b=c\\d", "key;with=specials" => "plain"};
+    let tagged = TaggedCommand {
+        command: Command::Ok,
+        tags,
+    };
+    let mesg = RawMessage::to_raw_tagged(&tagged).unwrap();
+    let parsed = RawMessage::from_raw_tagged(&mesg).unwrap();
+    assert_eq!(parsed, tagged);
+}
+
+#[test]
+fn test_msgpack_roundtrip() {
+    let command = Command::Msg("alice".to_string(), "bob".to_string(), "hi".to_string());
+    let encoded = RawMessage::to_msgpack(&command).unwrap();
+    let decoded = RawMessage::from_msgpack(&encoded).unwrap();
+    assert_eq!(decoded, command);
+}
+
+#[test]
+fn test_msgpack_not_bound_by_fixed_name_length() {
+    // A 32 Byte sender name, twice what the fixed layout's SENDER_BYTES
+    // field can hold, round-trips fine since msgpack has no such ceiling.
+    let long_name = "a".repeat(32);
+    let command = Command::Usr(long_name.clone(), "secret".to_string());
+    let encoded = RawMessage::to_msgpack(&command).unwrap();
+    let decoded = RawMessage::from_msgpack(&encoded).unwrap();
+    assert_eq!(decoded, command);
+}
+
+#[test]
+fn test_msgpack_rejects_garbage() {
+    let err = RawMessage::from_msgpack(&[0xc1, 0xc1, 0xc1]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_wire_format_dispatch_roundtrips_each_encoding() {
+    let command = Command::Msg("alice".to_string(), "bob".to_string(), "hi".to_string());
+    for format in [WireFormat::Fixed, WireFormat::Framed, WireFormat::MsgPack] {
+        let encoded = RawMessage::encode(format, &command).unwrap();
+        let decoded = RawMessage::decode(format, &encoded).unwrap();
+        assert_eq!(decoded, command);
+    }
+}